@@ -0,0 +1,144 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use concurrency_manager::ConcurrencyManager;
+use kvproto::kvrpcpb::{ReadIndexRequest, ReadIndexResponse};
+use kvproto::metapb::{Peer, Region};
+use kvproto::raft_serverpb::RaftMessage;
+use raft::eraftpb::MessageType;
+use test_pd_client::TestPdClient;
+use tikv::config::TiKvConfig;
+use tikv_util::HandyRwLock;
+
+use crate::transport_simulate::{Direction, Filter, RaftStoreRouter, RegionPacketFilter};
+
+/// A running node (real or simulated) that a test's [`Cluster`] can install
+/// send/recv [`Filter`]s on and fetch a raft router from.
+pub trait Simulator {
+    type Router: RaftStoreRouter + Clone;
+
+    fn get_router(&self, store_id: u64) -> Option<Self::Router>;
+    fn add_send_filter(&mut self, node_id: u64, filter: Box<dyn Filter>);
+    fn add_recv_filter(&mut self, node_id: u64, filter: Box<dyn Filter>);
+    fn clear_send_filters(&mut self, node_id: u64);
+    fn clear_recv_filters(&mut self, node_id: u64);
+    fn get_concurrency_manager(&self, store_id: u64) -> ConcurrencyManager;
+    fn read_index(&self, store_id: u64, req: ReadIndexRequest) -> ReadIndexResponse;
+}
+
+/// Drives a multi-node raftstore cluster under a single test: owns the
+/// simulated transport (`sim`) and the fake PD client, and exposes `must_*`
+/// helpers that block until an operation is durably observed.
+pub struct Cluster<T: Simulator> {
+    pub cfg: TiKvConfig,
+    pub pd_client: Arc<TestPdClient>,
+    pub sim: Arc<RwLock<T>>,
+}
+
+/// Hand-rolled rather than `#[derive(Clone)]` so cloning a `Cluster` doesn't
+/// spuriously require `T: Clone`: every field is itself cheap to clone
+/// (an owned config snapshot plus two `Arc`s), so a clone is just another
+/// handle onto the same simulated cluster — handy for driving two `must_*`
+/// calls concurrently from separate threads.
+impl<T: Simulator> Clone for Cluster<T> {
+    fn clone(&self) -> Self {
+        Cluster {
+            cfg: self.cfg.clone(),
+            pd_client: Arc::clone(&self.pd_client),
+            sim: Arc::clone(&self.sim),
+        }
+    }
+}
+
+impl<T: Simulator> Cluster<T> {
+    /// Returns the raft router for `store_id`, mirroring
+    /// `Simulator::get_router` so tests don't have to reach through
+    /// `cluster.sim.wl()` to pause and resume a message stream themselves.
+    pub fn get_router(&self, store_id: u64) -> Option<T::Router> {
+        self.sim.rl().get_router(store_id)
+    }
+
+    /// Blocks until `peer` is observed to have stopped sending
+    /// `MsgHeartbeat`/`MsgRequestPreVote` for a quiescent window, i.e. it has
+    /// entered hibernation.
+    ///
+    /// Implemented with a send-filter callback rather than polling
+    /// `Instant::now()` in the test body, so a timeout produces a clean
+    /// panic instead of a test that silently passes on a slow machine.
+    pub fn wait_for_hibernate(&mut self, region_id: u64, peer_id: u64, timeout: Duration) {
+        let quiet_for = Duration::from_millis(300);
+        let (tx, rx) = mpsc::sync_channel(1024);
+        let cb = Arc::new(move |msg: &RaftMessage| {
+            let ty = msg.get_message().get_msg_type();
+            if matches!(
+                ty,
+                MessageType::MsgHeartbeat | MessageType::MsgRequestPreVote
+            ) {
+                let _ = tx.send(());
+            }
+        }) as Arc<dyn Fn(&RaftMessage) + Send + Sync>;
+        let filter = Box::new(
+            RegionPacketFilter::new(region_id, peer_id)
+                .direction(Direction::Send)
+                .when(Arc::new(AtomicBool::new(false)))
+                .set_msg_callback(cb),
+        );
+        self.sim.wl().add_send_filter(peer_id, filter);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                panic!(
+                    "peer {} on region {} did not hibernate within {:?}",
+                    peer_id, region_id, timeout
+                );
+            }
+            match rx.recv_timeout(quiet_for.min(remaining)) {
+                Ok(()) => continue,
+                Err(mpsc::RecvTimeoutError::Timeout) => return,
+                Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+        }
+    }
+
+    /// Returns the [`ConcurrencyManager`] owned by the store at `store_id`,
+    /// so a test can assert on `max_ts` without threading the handle through
+    /// every layer manually.
+    pub fn get_concurrency_manager(&self, store_id: u64) -> ConcurrencyManager {
+        self.sim.rl().get_concurrency_manager(store_id)
+    }
+
+    /// Issues a `ReadIndexRequest` carrying `start_ts` directly to `peer` and
+    /// blocks for the response, panicking if it carries a `region_error`.
+    ///
+    /// Unlike `async_read_on_peer`-style helpers, which drive a full `Get`
+    /// through the read-index path and return the value, this surfaces the
+    /// raw read-index response so a test can assert on its side effects
+    /// (like `max_ts` advancement) without waiting for an application-level
+    /// read to resolve.
+    pub fn must_read_index_on_peer(
+        &mut self,
+        peer: Peer,
+        region: Region,
+        start_ts: u64,
+    ) -> ReadIndexResponse {
+        let mut req = ReadIndexRequest::default();
+        req.mut_context().set_region_id(region.get_id());
+        req.mut_context()
+            .set_region_epoch(region.get_region_epoch().clone());
+        req.mut_context().set_peer(peer.clone());
+        req.set_start_ts(start_ts);
+        let resp = self.sim.wl().read_index(peer.get_store_id(), req);
+        assert!(
+            !resp.has_region_error(),
+            "read index failed: {:?}",
+            resp.get_region_error()
+        );
+        resp
+    }
+}
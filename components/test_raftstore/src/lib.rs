@@ -0,0 +1,12 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Test-only scaffolding for spinning up a simulated TiKV/raftstore cluster
+//! and driving it from integration tests.
+
+mod cluster;
+pub mod transport_simulate;
+mod util;
+
+pub use cluster::*;
+pub use transport_simulate::*;
+pub use util::*;
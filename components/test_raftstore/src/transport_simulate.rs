@@ -0,0 +1,620 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Building blocks for intercepting and mutating the raft message stream in
+//! tests.
+//!
+//! A [`Filter`] sits in front of a node's raft transport and gets a chance to
+//! inspect (and drop or rewrite) every batch of [`RaftMessage`]s the node is
+//! about to send or has just received. Tests register filters through
+//! `Cluster::sim`'s `add_send_filter`/`add_recv_filter`.
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kvproto::raft_serverpb::RaftMessage;
+use raft::eraftpb::MessageType;
+use raftstore::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Minimal contract a simulated raft router needs for tests to re-inject
+/// messages a [`Filter`] previously intercepted.
+pub trait RaftStoreRouter: Send + Sync {
+    fn send_raft_message(&self, msg: RaftMessage) -> Result<()>;
+}
+
+/// Which leg of the transport a [`Filter`] is attached to.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Direction {
+    Send,
+    Recv,
+    Both,
+}
+
+impl Direction {
+    pub fn is_send(&self) -> bool {
+        matches!(self, Direction::Send | Direction::Both)
+    }
+
+    pub fn is_recv(&self) -> bool {
+        matches!(self, Direction::Recv | Direction::Both)
+    }
+}
+
+/// Intercepts a batch of raft messages before/after they hit the wire.
+///
+/// `before` may drop messages from `msgs` (e.g. via `retain`) or mutate them
+/// in place; returning `Err` aborts the send/receive with that error. `after`
+/// observes the result of actually delivering whatever `before` left behind.
+pub trait Filter: Send + Sync {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()>;
+    fn after(&self, res: Result<()>) -> Result<()> {
+        res
+    }
+}
+
+impl<F: Filter + ?Sized> Filter for Arc<F> {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        (**self).before(msgs)
+    }
+
+    fn after(&self, res: Result<()>) -> Result<()> {
+        (**self).after(res)
+    }
+}
+
+/// Drops messages to/from a particular region+peer, optionally narrowed to a
+/// direction, a single [`MessageType`], or an on/off switch, and optionally
+/// stashing the dropped messages for later inspection.
+#[derive(Clone)]
+pub struct RegionPacketFilter {
+    region_id: u64,
+    peer_id: u64,
+    direction: Direction,
+    msg_type: Option<MessageType>,
+    when: Option<Arc<AtomicBool>>,
+    reserve_dropped: Option<Arc<Mutex<Vec<RaftMessage>>>>,
+    msg_callback: Option<Arc<dyn Fn(&RaftMessage) + Send + Sync>>,
+}
+
+impl RegionPacketFilter {
+    pub fn new(region_id: u64, peer_id: u64) -> Self {
+        RegionPacketFilter {
+            region_id,
+            peer_id,
+            direction: Direction::Both,
+            msg_type: None,
+            when: None,
+            reserve_dropped: None,
+            msg_callback: None,
+        }
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    pub fn msg_type(mut self, msg_type: MessageType) -> Self {
+        self.msg_type = Some(msg_type);
+        self
+    }
+
+    /// A switch this filter consults before dropping anything; while it reads
+    /// `false` the filter drops nothing (the message callback still runs).
+    pub fn when(mut self, when: Arc<AtomicBool>) -> Self {
+        self.when = Some(when);
+        self
+    }
+
+    pub fn reserve_dropped(mut self, dropped: Arc<Mutex<Vec<RaftMessage>>>) -> Self {
+        self.reserve_dropped = Some(dropped);
+        self
+    }
+
+    pub fn set_msg_callback(mut self, cb: Arc<dyn Fn(&RaftMessage) + Send + Sync>) -> Self {
+        self.msg_callback = Some(cb);
+        self
+    }
+
+    fn matches(&self, msg: &RaftMessage) -> bool {
+        if msg.get_region_id() != self.region_id {
+            return false;
+        }
+        if let Some(msg_type) = self.msg_type {
+            if msg.get_message().get_msg_type() != msg_type {
+                return false;
+            }
+        }
+        let (from, to) = (msg.get_from_peer().get_id(), msg.get_to_peer().get_id());
+        match self.direction {
+            Direction::Send => from == self.peer_id,
+            Direction::Recv => to == self.peer_id,
+            Direction::Both => from == self.peer_id || to == self.peer_id,
+        }
+    }
+}
+
+impl Filter for RegionPacketFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        if let Some(cb) = &self.msg_callback {
+            for msg in msgs.iter() {
+                if self.matches(msg) {
+                    cb(msg);
+                }
+            }
+        }
+        if self
+            .when
+            .as_ref()
+            .map_or(false, |w| !w.load(Ordering::Relaxed))
+        {
+            return Ok(());
+        }
+        let mut dropped = Vec::new();
+        msgs.retain(|m| {
+            if self.matches(m) {
+                dropped.push(m.clone());
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(reserve) = &self.reserve_dropped {
+            reserve.lock().unwrap().extend(dropped);
+        }
+        Ok(())
+    }
+}
+
+/// Drops every message of the given type, regardless of region or peer.
+///
+/// Handy as a building block for `AndFilter`/`OrFilter` combinations where a
+/// one-off [`RegionPacketFilter`] would otherwise have to be re-derived for
+/// each predicate.
+pub struct DropMessageFilter {
+    msg_type: MessageType,
+}
+
+impl DropMessageFilter {
+    pub fn new(msg_type: MessageType) -> Self {
+        DropMessageFilter { msg_type }
+    }
+}
+
+impl Filter for DropMessageFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| m.get_message().get_msg_type() != self.msg_type);
+        Ok(())
+    }
+}
+
+/// Intercepts messages, optionally narrowed to a [`MessageType`] and/or a
+/// peer+[`Direction`], and stashes them (in capture order) instead of just
+/// dropping them.
+///
+/// Promotes the record-then-reinject pattern tests otherwise hand-roll with
+/// an `Arc<Mutex<Vec<RaftMessage>>>` and `reserve_dropped` into a reusable
+/// primitive: call [`RecordReplayFilter::take_recorded`] to pause and
+/// inspect a message stream, then [`RecordReplayFilter::replay_into`] to
+/// resume it once the test is done poking at the cluster.
+pub struct RecordReplayFilter {
+    peer_id: Option<u64>,
+    direction: Direction,
+    msg_type: Option<MessageType>,
+    recorded: Arc<Mutex<Vec<RaftMessage>>>,
+}
+
+impl RecordReplayFilter {
+    pub fn new() -> Self {
+        RecordReplayFilter {
+            peer_id: None,
+            direction: Direction::Both,
+            msg_type: None,
+            recorded: Arc::default(),
+        }
+    }
+
+    pub fn msg_type(mut self, msg_type: MessageType) -> Self {
+        self.msg_type = Some(msg_type);
+        self
+    }
+
+    /// Narrows recording to messages to/from `peer_id`; combine with
+    /// `.direction(..)` to pick a single leg.
+    pub fn peer(mut self, peer_id: u64) -> Self {
+        self.peer_id = Some(peer_id);
+        self
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    fn matches(&self, msg: &RaftMessage) -> bool {
+        if let Some(msg_type) = self.msg_type {
+            if msg.get_message().get_msg_type() != msg_type {
+                return false;
+            }
+        }
+        if let Some(peer_id) = self.peer_id {
+            let (from, to) = (msg.get_from_peer().get_id(), msg.get_to_peer().get_id());
+            return match self.direction {
+                Direction::Send => from == peer_id,
+                Direction::Recv => to == peer_id,
+                Direction::Both => from == peer_id || to == peer_id,
+            };
+        }
+        true
+    }
+
+    /// Drains and returns everything recorded so far, in the order it was
+    /// intercepted.
+    pub fn take_recorded(&self) -> Vec<RaftMessage> {
+        mem::take(&mut *self.recorded.lock().unwrap())
+    }
+
+    /// Re-delivers `msgs` into `router` in order, e.g. the result of
+    /// [`RecordReplayFilter::take_recorded`].
+    pub fn replay_into(router: &impl RaftStoreRouter, msgs: Vec<RaftMessage>) -> Result<()> {
+        for msg in msgs {
+            router.send_raft_message(msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for RecordReplayFilter {
+    fn default() -> Self {
+        RecordReplayFilter::new()
+    }
+}
+
+impl Filter for RecordReplayFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut recorded = self.recorded.lock().unwrap();
+        msgs.retain(|m| {
+            if self.matches(m) {
+                recorded.push(m.clone());
+                false
+            } else {
+                true
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Injects network faults a plain drop/mutate [`Filter`] cannot express:
+/// held-then-released delay, batch reordering, and duplication.
+///
+/// Delayed messages are handed to a background thread that sleeps for a
+/// duration drawn from `[min, max]` and then redelivers them into `router`,
+/// so `before` itself never blocks the caller. All randomness (which
+/// messages are delayed/duplicated/reordered, how long the delay is) is
+/// drawn from a seeded [`StdRng`] so a flaky run can be reproduced by fixing
+/// the seed.
+///
+/// Like `delay`/`duplicate`, reordering is opt-in: it defaults to
+/// `reorder_probability: 0.0`, so a filter built with only `.duplicate(..)`
+/// or `.delay(..)` leaves message order on that node's transport untouched.
+///
+/// Every spawned delay thread is tracked in `pending` and joined either by
+/// calling [`NetworkChaosFilter::join_pending`] or when the filter itself is
+/// dropped, so a test can await in-flight deliveries instead of leaking
+/// threads that outlive the `Cluster`.
+pub struct NetworkChaosFilter<R> {
+    router: R,
+    delay_range: Option<(Duration, Duration)>,
+    delay_probability: f64,
+    duplicate_probability: f64,
+    reorder_probability: f64,
+    rng: Mutex<StdRng>,
+    pending: Arc<Mutex<Vec<std::thread::JoinHandle<()>>>>,
+}
+
+impl<R: RaftStoreRouter + Clone + Send + 'static> NetworkChaosFilter<R> {
+    pub fn new(router: R, seed: u64) -> Self {
+        NetworkChaosFilter {
+            router,
+            delay_range: None,
+            delay_probability: 0.0,
+            duplicate_probability: 0.0,
+            reorder_probability: 0.0,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Blocks until every delayed delivery spawned so far has completed.
+    ///
+    /// Call this before tearing down the router/`Cluster` the filter was
+    /// built with, so a test can assert on the fully-settled message stream
+    /// instead of racing the background deliveries.
+    pub fn join_pending(&self) {
+        let handles = mem::take(&mut *self.pending.lock().unwrap());
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+
+    /// Holds a `probability` fraction of messages for a delay drawn from
+    /// `[min, max]` before releasing them into the target router.
+    pub fn delay(mut self, probability: f64, min: Duration, max: Duration) -> Self {
+        self.delay_probability = probability;
+        self.delay_range = Some((min, max));
+        self
+    }
+
+    /// Redelivers a `probability` fraction of messages a second time,
+    /// in addition to the original delivery.
+    pub fn duplicate(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability;
+        self
+    }
+
+    /// Opts into shuffling the batch order: with `probability`, `before`
+    /// reorders the messages it receives (Fisher-Yates) before applying
+    /// duplication/delay. Defaults to `0.0`, i.e. off, so a filter built
+    /// with only `.delay(..)`/`.duplicate(..)` never reorders messages.
+    pub fn reorder(mut self, probability: f64) -> Self {
+        self.reorder_probability = probability;
+        self
+    }
+
+    fn roll(&self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        self.rng.lock().unwrap().gen_bool(probability.min(1.0))
+    }
+}
+
+impl<R: RaftStoreRouter + Clone + Send + 'static> Filter for NetworkChaosFilter<R> {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        // Shuffle the batch in place (Fisher-Yates), gated behind
+        // reorder_probability so filters that only opt into delay/duplicate
+        // don't also silently reorder messages.
+        if self.roll(self.reorder_probability) {
+            let mut rng = self.rng.lock().unwrap();
+            for i in (1..msgs.len()).rev() {
+                let j = rng.gen_range(0..=i);
+                msgs.swap(i, j);
+            }
+        }
+
+        let mut duplicated = Vec::new();
+        msgs.retain(|m| {
+            if self.roll(self.duplicate_probability) {
+                duplicated.push(m.clone());
+            }
+            if let Some((min, max)) = self.delay_range {
+                if self.roll(self.delay_probability) {
+                    let router = self.router.clone();
+                    let dur = {
+                        let mut rng = self.rng.lock().unwrap();
+                        if max > min {
+                            Duration::from_nanos(
+                                rng.gen_range(min.as_nanos() as u64..=max.as_nanos() as u64),
+                            )
+                        } else {
+                            min
+                        }
+                    };
+                    let msg = m.clone();
+                    let handle = std::thread::spawn(move || {
+                        std::thread::sleep(dur);
+                        let _ = router.send_raft_message(msg);
+                    });
+                    self.pending.lock().unwrap().push(handle);
+                    return false;
+                }
+            }
+            true
+        });
+        msgs.extend(duplicated);
+        Ok(())
+    }
+}
+
+impl<R> Drop for NetworkChaosFilter<R> {
+    fn drop(&mut self) {
+        let handles = mem::take(&mut *self.pending.lock().unwrap());
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Runs two filters in sequence, short-circuiting on the first error.
+///
+/// Unlike stacking two filters on the same node (which always run both),
+/// `AndFilter` is meant to be composed into a single logical predicate, e.g.
+/// alongside [`OrFilter`] and [`NotFilter`], before being registered once.
+pub struct AndFilter {
+    left: Box<dyn Filter>,
+    right: Box<dyn Filter>,
+}
+
+impl AndFilter {
+    pub fn new(left: Box<dyn Filter>, right: Box<dyn Filter>) -> Self {
+        AndFilter { left, right }
+    }
+}
+
+impl Filter for AndFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        self.left.before(msgs)?;
+        self.right.before(msgs)
+    }
+
+    fn after(&self, res: Result<()>) -> Result<()> {
+        self.right.after(self.left.after(res)?)
+    }
+}
+
+/// Applies both filters to independent copies of the batch and keeps only
+/// what survives both of them.
+///
+/// Useful for e.g. "drop `MsgReadIndex` to peer 3 OR delay `MsgAppend`",
+/// where a message should be affected if either predicate would affect it.
+///
+/// `left`/`right` must each only *drop* messages from the batch (like
+/// [`DropMessageFilter`] or a [`RegionPacketFilter`] predicate), never mutate
+/// a message's contents in place: survival is determined by comparing
+/// `RaftMessage` values against the pre-filter batch, so a filter that
+/// mutates (e.g. rewrites a field rather than dropping the message) will not
+/// be recognized as "kept" and its mutation will be silently lost. The same
+/// value comparison also can't distinguish two structurally-identical
+/// messages in the same batch — debug builds assert the batch holds no such
+/// duplicates (mutation is not detected).
+pub struct OrFilter {
+    left: Box<dyn Filter>,
+    right: Box<dyn Filter>,
+}
+
+impl OrFilter {
+    pub fn new(left: Box<dyn Filter>, right: Box<dyn Filter>) -> Self {
+        OrFilter { left, right }
+    }
+}
+
+impl Filter for OrFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        debug_assert!(
+            has_no_duplicates(msgs),
+            "OrFilter compares messages by value and cannot disambiguate \
+             two structurally-identical messages in the same batch"
+        );
+        let mut via_left = msgs.clone();
+        self.left.before(&mut via_left)?;
+        let mut via_right = msgs.clone();
+        self.right.before(&mut via_right)?;
+        msgs.retain(|m| via_left.iter().any(|k| k == m) && via_right.iter().any(|k| k == m));
+        Ok(())
+    }
+}
+
+/// Inverts a filter's effect: whatever the inner filter would have dropped is
+/// kept, and whatever it would have kept is dropped.
+///
+/// Like [`OrFilter`], `inner` must only drop messages rather than mutate
+/// them in place — see [`OrFilter`]'s doc comment for why a mutating inner
+/// filter or a batch with duplicate-by-value messages breaks this.
+pub struct NotFilter {
+    inner: Box<dyn Filter>,
+}
+
+impl NotFilter {
+    pub fn new(inner: Box<dyn Filter>) -> Self {
+        NotFilter { inner }
+    }
+}
+
+impl Filter for NotFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        debug_assert!(
+            has_no_duplicates(msgs),
+            "NotFilter compares messages by value and cannot disambiguate \
+             two structurally-identical messages in the same batch"
+        );
+        let original = msgs.clone();
+        self.inner.before(msgs)?;
+        let survived = msgs.clone();
+        *msgs = original
+            .into_iter()
+            .filter(|m| !survived.iter().any(|k| k == m))
+            .collect();
+        Ok(())
+    }
+}
+
+fn has_no_duplicates(msgs: &[RaftMessage]) -> bool {
+    for (i, a) in msgs.iter().enumerate() {
+        for b in &msgs[i + 1..] {
+            if a == b {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Wraps a filter so that whatever messages it would otherwise drop are
+/// instead held for `dur` and then redelivered into `router`, rather than
+/// lost forever.
+///
+/// Messages the inner filter keeps pass through immediately, unaffected.
+/// As in [`NetworkChaosFilter`], the hold runs on a background thread so
+/// `before` itself never blocks the caller; unlike there, the thread is
+/// detached and nothing tracks or joins it.
+pub struct DelayFilter<R> {
+    inner: Box<dyn Filter>,
+    dur: Duration,
+    router: R,
+}
+
+impl<R: RaftStoreRouter + Clone + Send + 'static> DelayFilter<R> {
+    pub fn new(inner: Box<dyn Filter>, dur: Duration, router: R) -> Self {
+        DelayFilter { inner, dur, router }
+    }
+}
+
+impl<R: RaftStoreRouter + Clone + Send + 'static> Filter for DelayFilter<R> {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let original = msgs.clone();
+        self.inner.before(msgs)?;
+        let survived = msgs.clone();
+        let held: Vec<RaftMessage> = original
+            .into_iter()
+            .filter(|m| !survived.iter().any(|k| k == m))
+            .collect();
+        if !held.is_empty() {
+            let router = self.router.clone();
+            let dur = self.dur;
+            std::thread::spawn(move || {
+                std::thread::sleep(dur);
+                for msg in held {
+                    let _ = router.send_raft_message(msg);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn after(&self, res: Result<()>) -> Result<()> {
+        self.inner.after(res)
+    }
+}
+
+/// Extension methods for turning any [`Filter`] into a combinator chain,
+/// e.g. `RegionPacketFilter::new(1, 3).msg_type(MessageType::MsgReadIndex)
+/// .and(DropMessageFilter::new(MessageType::MsgAppend).delay(d, router))`,
+/// which drops `MsgReadIndex` to or from peer 3 for good while holding `MsgAppend`
+/// for `d` before redelivering it through `router`.
+pub trait FilterComposeExt: Filter + Sized + 'static {
+    fn and(self, other: impl Filter + 'static) -> AndFilter {
+        AndFilter::new(Box::new(self), Box::new(other))
+    }
+
+    fn or(self, other: impl Filter + 'static) -> OrFilter {
+        OrFilter::new(Box::new(self), Box::new(other))
+    }
+
+    fn not(self) -> NotFilter {
+        NotFilter::new(Box::new(self))
+    }
+
+    fn delay<R: RaftStoreRouter + Clone + Send + 'static>(
+        self,
+        dur: Duration,
+        router: R,
+    ) -> DelayFilter<R> {
+        DelayFilter::new(Box::new(self), dur, router)
+    }
+}
+
+impl<T: Filter + Sized + 'static> FilterComposeExt for T {}
@@ -0,0 +1,36 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use concurrency_manager::ConcurrencyManager;
+use txn_types::TimeStamp;
+
+use tikv_util::config::ReadableDuration;
+
+use crate::{Cluster, Simulator};
+
+/// Sets the hibernate-region related timeouts to values short enough for a
+/// test to observe a peer enter hibernation in well under a second, instead
+/// of the minutes-scale production defaults.
+pub fn configure_for_hibernate<T: Simulator>(cluster: &mut Cluster<T>) {
+    // abnormal_leader_missing_duration must stay >= max_leader_missing_duration:
+    // it's the more severe threshold and config validation rejects the
+    // reverse ordering.
+    cluster.cfg.raft_store.max_leader_missing_duration = ReadableDuration::millis(500);
+    cluster.cfg.raft_store.abnormal_leader_missing_duration = ReadableDuration::millis(800);
+    cluster.cfg.raft_store.peer_stale_state_check_interval = ReadableDuration::millis(100);
+}
+
+/// Asserts `cm`'s `max_ts` has been advanced to at least `ts`.
+///
+/// This is the correctness property that lets async-commit/1PC transactions
+/// keep their commit-ts monotonic even when a `ReadIndexRequest` carrying
+/// `ts` lands on a follower or learner rather than the leader.
+pub fn must_advance_max_ts(cm: &ConcurrencyManager, ts: impl Into<TimeStamp>) {
+    let ts = ts.into();
+    let max_ts = cm.max_ts();
+    assert!(
+        max_ts >= ts,
+        "max_ts {:?} did not advance to at least {:?}",
+        max_ts,
+        ts
+    );
+}